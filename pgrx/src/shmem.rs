@@ -8,6 +8,7 @@ Use of this source code is governed by the MIT license that can be found in the
 */
 use crate::lwlock::*;
 use crate::{pg_sys, PgAtomic};
+use portable_atomic::Ordering;
 use std::hash::Hash;
 use uuid::Uuid;
 
@@ -21,8 +22,10 @@ pub unsafe trait PGRXSharedMemory {}
 ///
 /// > Types that allocate on the heap, such as `String` and `Vec` are not supported.
 ///
-/// For complex data structures like vecs and maps, `pgrx` prefers the use of types from
-/// [`heapless`](https://crates.io/crates/heapless).
+/// For complex data structures like vecs, maps, sets, and fixed-capacity owned strings,
+/// `pgrx` prefers the use of types from [`heapless`](https://crates.io/crates/heapless),
+/// whose inline storage has no heap pointers to get invalidated when a different
+/// backend maps the segment at a different address.
 ///
 /// Custom types need to also implement the `PGRXSharedMemory` trait.
 ///
@@ -41,6 +44,9 @@ pub unsafe trait PGRXSharedMemory {}
 /// // Rust atomics can be used without locks, wrapped in a `PgAtomic`
 /// static ATOMIC: PgAtomic<std::sync::atomic::AtomicBool> = PgAtomic::new();
 ///
+/// // `portable_atomic`'s 128-bit and floating-point atomics work the same way
+/// static WIDE_ATOMIC: PgAtomic<portable_atomic::AtomicU128> = PgAtomic::new();
+///
 /// #[pg_guard]
 /// pub extern "C" fn _PG_init() {
 ///     pg_shmem_init!(PRIMITIVE);
@@ -135,7 +141,7 @@ where
 
 impl<T> PgSharedMemoryInitialization for PgAtomic<T>
 where
-    T: atomic_traits::Atomic + Default,
+    T: PgAtomicOps,
 {
     fn pg_init(&'static self) {
         PgSharedMem::pg_init_atomic(self);
@@ -146,6 +152,76 @@ where
     }
 }
 
+/// Marker bound satisfied by every type that [`PgAtomic`] is allowed to place in shared
+/// memory.
+///
+/// `atomic_traits::Atomic` covers everything in `core::sync::atomic`, which is exactly
+/// the set of atomics a target supports natively lock-free -- in practice, integers up
+/// to pointer width. That rules out 128-bit integers and every floating-point atomic on
+/// every platform, since `core` simply doesn't offer them. This trait widens `PgAtomic`'s
+/// bound to also accept the matching types from the [`portable_atomic`] crate: on
+/// x86_64 its 128-bit atomics compile down to `cmpxchg16b`, and on aarch64 to the
+/// FEAT_LSE atomic instructions, in both cases chosen via *runtime* CPU feature
+/// detection ("outline-atomics"), falling back to a seqlock when neither is available.
+///
+/// # Safety
+///
+/// `shmem_init_atomic` initializes a `PgAtomic<T>` by `std::ptr::copy`-ing a
+/// freshly-constructed `T::default()` byte-for-byte into the `ShmemInitStruct`-backed
+/// region, and every backend attaches to that same region independently. Implementors
+/// must therefore be plain value types containing no pointers into process-local
+/// memory, and any internal fallback synchronization (such as portable_atomic's seqlock)
+/// must be embedded in the atomic's own storage -- and thus live in the shared segment
+/// itself -- rather than reaching out to a lock that only one backend's process can see.
+/// `pg_init_atomic`/`shmem_init_atomic` enforce this for the `portable_atomic` types via
+/// [`assert_safe_for_shmem`][Self::assert_safe_for_shmem], since whether their fallback
+/// path (and thus a process-local lock) can be selected is only known at runtime.
+pub unsafe trait PgAtomicOps: Default {
+    /// Panics if this type's fallback (non-lock-free) path could be selected at
+    /// runtime, which for the `portable_atomic` wide/float types would mean
+    /// synchronizing through a process-local lock table instead of state embedded in
+    /// the atomic's own shared-memory bytes. Called from `pg_init_atomic` and
+    /// `shmem_init_atomic` so a backend can't accidentally attach to a `PgAtomic` whose
+    /// concrete type isn't safe to share across processes on this platform.
+    fn assert_safe_for_shmem() {}
+}
+
+unsafe impl<T> PgAtomicOps for T where T: atomic_traits::Atomic + Default {}
+
+macro_rules! impl_portable_atomic_ops {
+    ($($t:ty),* $(,)?) => {
+        $(
+            unsafe impl PgAtomicOps for $t {
+                fn assert_safe_for_shmem() {
+                    // `IS_ALWAYS_LOCK_FREE` is a *compile-time* constant (e.g. whether
+                    // `cmpxchg16b` was enabled via `RUSTFLAGS`) and is `false` on an
+                    // ordinary build even on hardware that supports the instruction.
+                    // `is_lock_free()` is portable_atomic's actual outline-atomics
+                    // check, performed at runtime against the CPU this backend is
+                    // actually running on, which is what the always-lock-free
+                    // invariant above depends on.
+                    assert!(
+                        <$t>::is_lock_free(),
+                        concat!(
+                            stringify!($t),
+                            " is not lock-free on this CPU -- its fallback path \
+                             synchronizes through a process-local lock table, which is unsound \
+                             to share across Postgres backends",
+                        ),
+                    );
+                }
+            }
+        )*
+    };
+}
+
+impl_portable_atomic_ops!(
+    portable_atomic::AtomicU128,
+    portable_atomic::AtomicI128,
+    portable_atomic::AtomicF32,
+    portable_atomic::AtomicF64,
+);
+
 /// This struct contains methods to drive creation of types in shared memory
 pub struct PgSharedMem {}
 
@@ -160,7 +236,8 @@ impl PgSharedMem {
     }
 
     /// Must be run from _PG_init for atomics
-    pub fn pg_init_atomic<T: atomic_traits::Atomic + Default>(_atomic: &PgAtomic<T>) {
+    pub fn pg_init_atomic<T: PgAtomicOps>(_atomic: &PgAtomic<T>) {
+        T::assert_safe_for_shmem();
         unsafe {
             pg_sys::RequestAddinShmemSpace(std::mem::size_of::<T>());
         }
@@ -187,7 +264,8 @@ impl PgSharedMem {
     }
 
     /// Must be run from the shared memory init hook, use for rust atomics behind `PgAtomic`
-    pub fn shmem_init_atomic<T: atomic_traits::Atomic + Default>(atomic: &PgAtomic<T>) {
+    pub fn shmem_init_atomic<T: PgAtomicOps>(atomic: &PgAtomic<T>) {
+        T::assert_safe_for_shmem();
         unsafe {
             let shm_name = alloc::ffi::CString::new(Uuid::new_v4().to_string())
                 .expect("CString::new() failed");
@@ -209,6 +287,628 @@ impl PgSharedMem {
     }
 }
 
+/// A condition variable that lives in shared memory, wrapping Postgres'
+/// `ConditionVariable` API so one backend can sleep until another mutates some shared
+/// state.
+///
+/// Unlike [`PgLwLock`], a `PgCondVar` carries no data of its own -- pair it with a
+/// `PgLwLock`-guarded predicate: acquire the lock, check the condition, release the
+/// lock, then `wait()`. Because `ConditionVariableSleep` can return on a spurious
+/// wakeup, always re-check the predicate in a loop after waking up.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgrx::prelude::*;
+/// use pgrx::{pg_shmem_init, PgCondVar, PgLwLock, PgSharedMemoryInitialization};
+///
+/// static READY: PgLwLock<bool> = PgLwLock::new();
+/// static READY_CONDVAR: PgCondVar = PgCondVar::new();
+///
+/// #[pg_guard]
+/// pub extern "C" fn _PG_init() {
+///     pg_shmem_init!(READY);
+///     pg_shmem_init!(READY_CONDVAR);
+/// }
+///
+/// fn wait_until_ready() {
+///     let mut guard = READY.exclusive();
+///     while !*guard {
+///         drop(guard);
+///         READY_CONDVAR.wait();
+///         guard = READY.exclusive();
+///     }
+/// }
+/// ```
+pub struct PgCondVar {
+    inner: std::cell::UnsafeCell<Option<std::ptr::NonNull<pg_sys::ConditionVariable>>>,
+}
+
+unsafe impl Send for PgCondVar {}
+unsafe impl Sync for PgCondVar {}
+unsafe impl PGRXSharedMemory for PgCondVar {}
+
+impl PgCondVar {
+    pub const fn new() -> Self {
+        PgCondVar { inner: std::cell::UnsafeCell::new(None) }
+    }
+
+    fn attach(&self, cv: *mut pg_sys::ConditionVariable) {
+        unsafe {
+            *self.inner.get() = std::ptr::NonNull::new(cv);
+        }
+    }
+
+    fn as_ptr(&self) -> *mut pg_sys::ConditionVariable {
+        unsafe {
+            (*self.inner.get())
+                .expect("PgCondVar has not been initialized via `pg_shmem_init!()`")
+                .as_ptr()
+        }
+    }
+
+    /// Sleep until another backend calls [`notify_one`][Self::notify_one] or
+    /// [`notify_all`][Self::notify_all]. May wake up spuriously, so callers must
+    /// re-check their predicate after this returns.
+    pub fn wait(&self) {
+        unsafe {
+            pg_sys::ConditionVariablePrepareToSleep(self.as_ptr());
+            pg_sys::ConditionVariableSleep(
+                self.as_ptr(),
+                pg_sys::WaitEventExtension_WAIT_EVENT_EXTENSION,
+            );
+            pg_sys::ConditionVariableCancelSleep();
+        }
+    }
+
+    /// Like [`wait`][Self::wait], but gives up after `timeout` elapses. Returns `true`
+    /// if the wait timed out, `false` if it was woken by a notification (which, as
+    /// with `wait`, can be spurious).
+    pub fn wait_timeout(&self, timeout: std::time::Duration) -> bool {
+        unsafe {
+            pg_sys::ConditionVariablePrepareToSleep(self.as_ptr());
+            // `ConditionVariableTimedSleep` itself returns `true` on timeout and
+            // `false` when woken by a notification -- return that as-is.
+            let timed_out = pg_sys::ConditionVariableTimedSleep(
+                self.as_ptr(),
+                timeout.as_millis() as std::os::raw::c_long,
+                pg_sys::WaitEventExtension_WAIT_EVENT_EXTENSION,
+            );
+            pg_sys::ConditionVariableCancelSleep();
+            timed_out
+        }
+    }
+
+    /// Wake exactly one backend sleeping on this condition variable, if any.
+    pub fn notify_one(&self) {
+        unsafe {
+            pg_sys::ConditionVariableSignal(self.as_ptr());
+        }
+    }
+
+    /// Wake every backend sleeping on this condition variable.
+    pub fn notify_all(&self) {
+        unsafe {
+            pg_sys::ConditionVariableBroadcast(self.as_ptr());
+        }
+    }
+}
+
+impl Default for PgCondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PgSharedMemoryInitialization for PgCondVar {
+    fn pg_init(&'static self) {
+        unsafe {
+            pg_sys::RequestAddinShmemSpace(std::mem::size_of::<pg_sys::ConditionVariable>());
+        }
+    }
+
+    fn shmem_init(&'static self) {
+        unsafe {
+            let shm_name = alloc::ffi::CString::new(Uuid::new_v4().to_string())
+                .expect("CString::new() failed");
+            let addin_shmem_init_lock: *mut pg_sys::LWLock =
+                &mut (*pg_sys::MainLWLockArray.add(21)).lock;
+
+            let mut found = false;
+            pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+            let cv = pg_sys::ShmemInitStruct(
+                shm_name.into_raw(),
+                std::mem::size_of::<pg_sys::ConditionVariable>(),
+                &mut found,
+            ) as *mut pg_sys::ConditionVariable;
+
+            if !found {
+                pg_sys::ConditionVariableInit(cv);
+            }
+            self.attach(cv);
+            pg_sys::LWLockRelease(addin_shmem_init_lock);
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod condvar_tests {
+    use super::*;
+    use std::time::Duration;
+
+    static TEST_READY: PgLwLock<bool> = PgLwLock::new();
+    static TEST_CONDVAR: PgCondVar = PgCondVar::new();
+
+    #[pg_guard]
+    pub extern "C" fn _PG_init() {
+        pg_shmem_init!(TEST_READY);
+        pg_shmem_init!(TEST_CONDVAR);
+    }
+
+    #[pg_test]
+    fn test_condvar_wait_timeout_without_notify() {
+        // nothing notifies this condvar, so a short wait must time out
+        assert!(TEST_CONDVAR.wait_timeout(Duration::from_millis(50)));
+    }
+
+    #[pg_test]
+    fn test_condvar_notify_one_wakes_waiter() {
+        *TEST_READY.exclusive() = false;
+
+        // emulate a second backend: flip the predicate and notify only after this
+        // backend has had time to start waiting, so the assertions below actually
+        // exercise the notify-wakes-a-waiter path rather than finding the predicate
+        // already true.
+        let notifier = std::thread::spawn(|| {
+            std::thread::sleep(Duration::from_millis(200));
+            *TEST_READY.exclusive() = true;
+            TEST_CONDVAR.notify_one();
+        });
+
+        let mut woken_by_notify = false;
+        let mut guard = TEST_READY.exclusive();
+        while !*guard {
+            drop(guard);
+            if !TEST_CONDVAR.wait_timeout(Duration::from_secs(5)) {
+                woken_by_notify = true;
+            }
+            guard = TEST_READY.exclusive();
+        }
+        drop(guard);
+
+        notifier.join().expect("notifier thread panicked");
+        assert!(woken_by_notify, "wait_timeout never returned due to a notification");
+    }
+}
+
+/// A value that lives in shared memory but holds no `LWLock` of its own.
+///
+/// `pg_shmem_init!`-ing a [`PgLwLock`] per shared structure is fine for one or two
+/// values, but an extension with a dozen related structures ends up burning a dozen
+/// named tranches and forcing fine-grained locking that can deadlock when a backend
+/// needs two of them at once. `LockedBy<T, L>` instead stores `T` in shared memory
+/// under the tranche of a designated *guardian* [`PgLwLock<L>`], and every accessor
+/// requires the caller to present a live guard of a `PgLwLock<L>`, so calling one
+/// without holding a lock is a compile error -- without the guardian's own data, `L`,
+/// having anything to do with `T`. The check is by type only: which `PgLwLock<L>` the
+/// guard came from is not checked, so a guard of any other lock whose data type is
+/// also `L` is accepted as well.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use pgrx::prelude::*;
+/// use pgrx::{pg_shmem_init, LockedBy, PgLwLock, PgSharedMemoryInitialization};
+///
+/// static TRANCHE_LOCK: PgLwLock<()> = PgLwLock::new();
+/// static COUNTERS: LockedBy<[i64; 16], ()> = LockedBy::new(&TRANCHE_LOCK);
+///
+/// #[pg_guard]
+/// pub extern "C" fn _PG_init() {
+///     pg_shmem_init!(TRANCHE_LOCK);
+///     pg_shmem_init!(COUNTERS);
+/// }
+///
+/// fn bump(i: usize) {
+///     let mut guard = TRANCHE_LOCK.exclusive();
+///     COUNTERS.exclusive(&mut guard)[i] += 1;
+/// }
+/// ```
+pub struct LockedBy<T, L> {
+    owner: &'static PgLwLock<L>,
+    inner: std::cell::UnsafeCell<Option<std::ptr::NonNull<T>>>,
+}
+
+unsafe impl<T: Send + PGRXSharedMemory, L> Send for LockedBy<T, L> {}
+unsafe impl<T: Send + PGRXSharedMemory, L> Sync for LockedBy<T, L> {}
+unsafe impl<T: PGRXSharedMemory, L> PGRXSharedMemory for LockedBy<T, L> {}
+
+impl<T, L> LockedBy<T, L> {
+    /// Creates a new `LockedBy`, naming the [`PgLwLock`] that will guard it.
+    ///
+    /// `owner` need not (and typically won't) hold a `T` itself -- it's only ever used
+    /// to name the shared memory this value is allocated under. `share`/`exclusive`
+    /// take a guard of any `PgLwLock<L>`; they do not check that it is `owner`'s.
+    pub const fn new(owner: &'static PgLwLock<L>) -> Self {
+        LockedBy { owner, inner: std::cell::UnsafeCell::new(None) }
+    }
+
+    fn attach(&self, ptr: *mut T) {
+        unsafe {
+            *self.inner.get() = std::ptr::NonNull::new(ptr);
+        }
+    }
+
+    fn as_ptr(&self) -> *mut T {
+        unsafe {
+            (*self.inner.get())
+                .expect("LockedBy has not been initialized via `pg_shmem_init!()`")
+                .as_ptr()
+        }
+    }
+
+    /// Borrow the value, given proof that its guardian lock is held for reading.
+    pub fn share<'a>(&'a self, _guard: &'a PgLwLockShareGuard<'a, L>) -> &'a T {
+        unsafe { &*self.as_ptr() }
+    }
+
+    /// Borrow the value mutably, given proof that its guardian lock is held exclusively.
+    ///
+    /// Takes the guard by unique reference, not by shared reference or by value: by
+    /// value would let the guard -- and with it, the LWLock it holds -- drop at the end
+    /// of this call, while the returned `&'a mut T` is still tied to the caller's
+    /// (now-lockless) scope. Borrowing it uniquely keeps the guard, and the lock, alive
+    /// for exactly as long as the returned reference, while still making it impossible
+    /// to call `exclusive` a second time with the same guard live, since Rust won't let
+    /// two `&mut` borrows of it coexist.
+    pub fn exclusive<'a>(&'a self, _guard: &'a mut PgLwLockExclusiveGuard<'a, L>) -> &'a mut T {
+        unsafe { &mut *self.as_ptr() }
+    }
+}
+
+impl<T, L> PgSharedMemoryInitialization for LockedBy<T, L>
+where
+    T: Default + PGRXSharedMemory + 'static,
+    L: 'static,
+{
+    /// Requests only the shared space this value needs -- the tranche itself is
+    /// requested once, by the guardian `PgLwLock<L>`'s own `pg_shmem_init!()`.
+    fn pg_init(&'static self) {
+        unsafe {
+            pg_sys::RequestAddinShmemSpace(std::mem::size_of::<T>());
+        }
+    }
+
+    fn shmem_init(&'static self) {
+        unsafe {
+            let shm_name =
+                alloc::ffi::CString::new(format!(
+                    "{}_locked_by_{}",
+                    self.owner.get_name(),
+                    Uuid::new_v4()
+                ))
+                    .expect("CString::new failed");
+            let addin_shmem_init_lock: *mut pg_sys::LWLock =
+                &mut (*pg_sys::MainLWLockArray.add(21)).lock;
+
+            let mut found = false;
+            pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+            let ptr = pg_sys::ShmemInitStruct(shm_name.into_raw(), std::mem::size_of::<T>(), &mut found)
+                as *mut T;
+
+            if !found {
+                std::ptr::write(ptr, <T>::default());
+            }
+            self.attach(ptr);
+            pg_sys::LWLockRelease(addin_shmem_init_lock);
+        }
+    }
+}
+
+/// Sentinel a backend publishes into its reservation slot when it is not inside a
+/// [`pinned`][PgEpochQueue::pinned]/`push`/`pop` call, so the consumer never waits on
+/// an idle backend when deciding whether a retired slot is safe to recycle.
+const UNPINNED_EPOCH: u64 = u64::MAX;
+
+/// Sentinel "no next slot" value terminating the intrusive free-list chain.
+const FREE_LIST_NIL: usize = usize::MAX;
+
+const ORDER_EMPTY: u8 = 0;
+const ORDER_READY: u8 = 1;
+
+/// Number of rotating garbage buckets retired slot indices cycle through. Three gives
+/// every backend that was pinned when a slot was retired at least one full bucket's
+/// worth of headroom to finish and unpin before that bucket comes back around to be
+/// drained.
+const GARBAGE_BUCKETS: usize = 3;
+
+/// A lock-free, multi-producer/single-consumer queue backed by a fixed shared arena of
+/// `CAPACITY` slots, with slot reuse guarded by epoch-based reclamation instead of a
+/// big lock -- unlike `LockedBy`'s single-lock model, producers here never block each
+/// other.
+///
+/// Slot indices, not ring positions, are what get reused: `push` pops a free index off
+/// an intrusive lock-free free-list and writes its value there, then CASes the shared
+/// `tail` to publish which index landed at that position. The single consumer reads
+/// positions off `head` in order, and once it has drained a slot it *retires* the index
+/// into a rotating garbage bucket rather than freeing it immediately -- a retired index
+/// is only pushed back onto the free-list, where `push` can hand it to a new producer,
+/// once every backend's reservation (published by [`pinned`][Self::pinned] before it
+/// touches the arena) has advanced past the epoch the index was retired at. `MAX_BACKENDS`
+/// must be at least Postgres' `MaxBackends`; indices into the arena are stored
+/// everywhere instead of raw pointers, since backends map this queue's shared memory
+/// segment at independent base addresses.
+#[repr(C)]
+pub struct PgEpochQueue<T, const CAPACITY: usize, const MAX_BACKENDS: usize> {
+    inner:
+        std::cell::UnsafeCell<Option<std::ptr::NonNull<EpochQueueShared<T, CAPACITY, MAX_BACKENDS>>>>,
+}
+
+unsafe impl<T: Send + PGRXSharedMemory, const CAPACITY: usize, const MAX_BACKENDS: usize> Send
+    for PgEpochQueue<T, CAPACITY, MAX_BACKENDS>
+{
+}
+unsafe impl<T: Send + PGRXSharedMemory, const CAPACITY: usize, const MAX_BACKENDS: usize> Sync
+    for PgEpochQueue<T, CAPACITY, MAX_BACKENDS>
+{
+}
+unsafe impl<T: Send + PGRXSharedMemory, const CAPACITY: usize, const MAX_BACKENDS: usize>
+    PGRXSharedMemory for PgEpochQueue<T, CAPACITY, MAX_BACKENDS>
+{
+}
+
+#[repr(C)]
+struct EpochQueueShared<T, const CAPACITY: usize, const MAX_BACKENDS: usize> {
+    global_epoch: portable_atomic::AtomicU64,
+    reservations: [portable_atomic::AtomicU64; MAX_BACKENDS],
+    head: portable_atomic::AtomicUsize,
+    tail: portable_atomic::AtomicUsize,
+    /// `order[pos % CAPACITY]` names which arena slot holds the value logically at
+    /// ring position `pos`, published via `order_ready[pos % CAPACITY]`.
+    order: [portable_atomic::AtomicUsize; CAPACITY],
+    order_ready: [portable_atomic::AtomicU8; CAPACITY],
+    /// Head of the intrusive Treiber stack of currently-unused arena slots.
+    free_head: portable_atomic::AtomicUsize,
+    free_next: [portable_atomic::AtomicUsize; CAPACITY],
+    slots: [std::cell::UnsafeCell<std::mem::MaybeUninit<T>>; CAPACITY],
+    garbage: [GarbageBucket<CAPACITY>; GARBAGE_BUCKETS],
+}
+
+struct GarbageBucket<const CAPACITY: usize> {
+    /// Epoch at which the indices currently in this bucket were retired. Only
+    /// meaningful while `len > 0`.
+    retired_at: portable_atomic::AtomicU64,
+    len: portable_atomic::AtomicUsize,
+    indices: [portable_atomic::AtomicUsize; CAPACITY],
+}
+
+impl<T, const CAPACITY: usize, const MAX_BACKENDS: usize> PgEpochQueue<T, CAPACITY, MAX_BACKENDS> {
+    pub const fn new() -> Self {
+        PgEpochQueue { inner: std::cell::UnsafeCell::new(None) }
+    }
+
+    fn attach(&self, ptr: *mut EpochQueueShared<T, CAPACITY, MAX_BACKENDS>) {
+        unsafe {
+            *self.inner.get() = std::ptr::NonNull::new(ptr);
+        }
+    }
+
+    fn shared(&self) -> &EpochQueueShared<T, CAPACITY, MAX_BACKENDS> {
+        unsafe {
+            (*self.inner.get())
+                .expect("PgEpochQueue has not been initialized via `pg_shmem_init!()`")
+                .as_ref()
+        }
+    }
+
+    fn backend_slot(&self) -> usize {
+        // `MyBackendId` runs from 1 to `MaxBackends`, so slot `id - 1` is this backend's
+        let backend_id = unsafe { pg_sys::MyBackendId };
+        assert!(
+            backend_id >= 1 && (backend_id as usize) <= MAX_BACKENDS,
+            "PgEpochQueue's MAX_BACKENDS is smaller than this cluster's MaxBackends"
+        );
+        (backend_id - 1) as usize
+    }
+
+    /// Publishes the current global epoch into this backend's reservation slot for the
+    /// duration of `f`, so the consumer won't recycle any slot this backend might
+    /// still be touching. Every `push`/`pop` runs through this.
+    fn pinned<R>(&self, f: impl FnOnce(&EpochQueueShared<T, CAPACITY, MAX_BACKENDS>) -> R) -> R {
+        let shared = self.shared();
+        let slot = self.backend_slot();
+        shared.reservations[slot]
+            .store(shared.global_epoch.load(Ordering::Acquire), Ordering::Release);
+        let result = f(shared);
+        shared.reservations[slot].store(UNPINNED_EPOCH, Ordering::Release);
+        result
+    }
+
+    /// Pops a free arena slot index off the free-list, if one is available.
+    fn free_pop(shared: &EpochQueueShared<T, CAPACITY, MAX_BACKENDS>) -> Option<usize> {
+        loop {
+            let head = shared.free_head.load(Ordering::Acquire);
+            if head == FREE_LIST_NIL {
+                return None;
+            }
+            let next = shared.free_next[head].load(Ordering::Acquire);
+            if shared
+                .free_head
+                .compare_exchange(head, next, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Some(head);
+            }
+        }
+    }
+
+    /// Pushes an arena slot index back onto the free-list.
+    fn free_push(shared: &EpochQueueShared<T, CAPACITY, MAX_BACKENDS>, idx: usize) {
+        loop {
+            let head = shared.free_head.load(Ordering::Acquire);
+            shared.free_next[idx].store(head, Ordering::Release);
+            if shared
+                .free_head
+                .compare_exchange(head, idx, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Enqueues `value`, returning it back if the queue is full (i.e. the free-list has
+    /// no slot available). Safe to call from any number of concurrent producer
+    /// backends; retries past lost CAS races rather than treating them as "full".
+    pub fn push(&self, value: T) -> Result<(), T> {
+        self.pinned(|shared| {
+            let idx = match Self::free_pop(shared) {
+                Some(idx) => idx,
+                None => return Err(value),
+            };
+            unsafe {
+                (*shared.slots[idx].get()).write(value);
+            }
+
+            loop {
+                let tail = shared.tail.load(Ordering::Acquire);
+                if shared
+                    .tail
+                    .compare_exchange(tail, tail.wrapping_add(1), Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // another producer claimed this tail position first -- retry
+                    continue;
+                }
+                let pos = tail % CAPACITY;
+                shared.order[pos].store(idx, Ordering::Relaxed);
+                shared.order_ready[pos].store(ORDER_READY, Ordering::Release);
+                return Ok(());
+            }
+        })
+    }
+
+    /// Dequeues the oldest value, if any, retiring its slot for epoch-delayed reuse.
+    /// Must only ever be called by the single designated consumer backend -- unlike
+    /// `push`, concurrent callers here would race on `head`.
+    pub fn pop(&self) -> Option<T> {
+        self.pinned(|shared| {
+            let head = shared.head.load(Ordering::Acquire);
+            if head == shared.tail.load(Ordering::Acquire) {
+                return None;
+            }
+            let pos = head % CAPACITY;
+            if shared.order_ready[pos].load(Ordering::Acquire) != ORDER_READY {
+                // the producer has claimed this position but hasn't published its slot yet
+                return None;
+            }
+            let idx = shared.order[pos].load(Ordering::Acquire);
+            shared.order_ready[pos].store(ORDER_EMPTY, Ordering::Relaxed);
+            shared.head.store(head.wrapping_add(1), Ordering::Release);
+
+            let value = unsafe { (*shared.slots[idx].get()).assume_init_read() };
+
+            let epoch = shared.global_epoch.fetch_add(1, Ordering::AcqRel);
+            self.retire(shared, idx, epoch);
+            Some(value)
+        })
+    }
+
+    /// Puts `idx` into the garbage bucket for `epoch`. A bucket's contents only go back
+    /// onto the free-list -- where `push` can hand them to a new producer -- once every
+    /// currently-pinned backend's reservation has advanced past the epoch that bucket
+    /// was last retired at, which is checked here before this retirement reuses the
+    /// bucket.
+    fn retire(&self, shared: &EpochQueueShared<T, CAPACITY, MAX_BACKENDS>, idx: usize, epoch: u64) {
+        let bucket = &shared.garbage[(epoch as usize) % GARBAGE_BUCKETS];
+
+        let min_reservation =
+            shared.reservations.iter().map(|r| r.load(Ordering::Acquire)).min().unwrap_or(u64::MAX);
+        if bucket.len.load(Ordering::Acquire) > 0
+            && min_reservation > bucket.retired_at.load(Ordering::Acquire)
+        {
+            // every pinned backend has moved past this bucket's retirement epoch --
+            // safe to hand its slots back to producers via the free-list.
+            let len = bucket.len.swap(0, Ordering::AcqRel);
+            for i in 0..len {
+                let reclaimed = bucket.indices[i].load(Ordering::Acquire);
+                Self::free_push(shared, reclaimed);
+            }
+        }
+
+        let len = bucket.len.fetch_add(1, Ordering::AcqRel);
+        bucket.indices[len].store(idx, Ordering::Release);
+        bucket.retired_at.store(epoch, Ordering::Release);
+    }
+}
+
+impl<T, const CAPACITY: usize, const MAX_BACKENDS: usize> PgSharedMemoryInitialization
+    for PgEpochQueue<T, CAPACITY, MAX_BACKENDS>
+where
+    T: Send + PGRXSharedMemory + 'static,
+{
+    fn pg_init(&'static self) {
+        unsafe {
+            pg_sys::RequestAddinShmemSpace(std::mem::size_of::<
+                EpochQueueShared<T, CAPACITY, MAX_BACKENDS>,
+            >());
+        }
+    }
+
+    fn shmem_init(&'static self) {
+        unsafe {
+            let shm_name = alloc::ffi::CString::new(Uuid::new_v4().to_string())
+                .expect("CString::new() failed");
+            let addin_shmem_init_lock: *mut pg_sys::LWLock =
+                &mut (*pg_sys::MainLWLockArray.add(21)).lock;
+
+            let mut found = false;
+            pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+            let ptr = pg_sys::ShmemInitStruct(
+                shm_name.into_raw(),
+                std::mem::size_of::<EpochQueueShared<T, CAPACITY, MAX_BACKENDS>>(),
+                &mut found,
+            ) as *mut EpochQueueShared<T, CAPACITY, MAX_BACKENDS>;
+
+            if !found {
+                std::ptr::write(
+                    ptr,
+                    EpochQueueShared {
+                        global_epoch: portable_atomic::AtomicU64::new(0),
+                        reservations: std::array::from_fn(|_| {
+                            portable_atomic::AtomicU64::new(UNPINNED_EPOCH)
+                        }),
+                        head: portable_atomic::AtomicUsize::new(0),
+                        tail: portable_atomic::AtomicUsize::new(0),
+                        order: std::array::from_fn(|_| portable_atomic::AtomicUsize::new(0)),
+                        order_ready: std::array::from_fn(|_| portable_atomic::AtomicU8::new(ORDER_EMPTY)),
+                        // Chain every slot onto the free-list up front: index `i` points
+                        // at `i - 1`, with slot `CAPACITY - 1` on top and slot `0`
+                        // terminating the chain.
+                        free_head: portable_atomic::AtomicUsize::new(
+                            if CAPACITY == 0 { FREE_LIST_NIL } else { CAPACITY - 1 },
+                        ),
+                        free_next: std::array::from_fn(|i| {
+                            portable_atomic::AtomicUsize::new(if i == 0 { FREE_LIST_NIL } else { i - 1 })
+                        }),
+                        slots: std::array::from_fn(|_| {
+                            std::cell::UnsafeCell::new(std::mem::MaybeUninit::uninit())
+                        }),
+                        garbage: std::array::from_fn(|_| GarbageBucket {
+                            retired_at: portable_atomic::AtomicU64::new(0),
+                            len: portable_atomic::AtomicUsize::new(0),
+                            indices: std::array::from_fn(|_| portable_atomic::AtomicUsize::new(0)),
+                        }),
+                    },
+                );
+            }
+            self.attach(ptr);
+            pg_sys::LWLockRelease(addin_shmem_init_lock);
+        }
+    }
+}
+
 unsafe impl PGRXSharedMemory for bool {}
 unsafe impl PGRXSharedMemory for char {}
 unsafe impl PGRXSharedMemory for str {}
@@ -264,3 +964,25 @@ unsafe impl<K: Eq + Hash, V: Default, S, const N: usize> PGRXSharedMemory
     for heapless::IndexMap<K, V, S, N>
 {
 }
+// `String<N>` is a `Vec<u8, N>` underneath plus a UTF-8 invariant on those same N
+// inline bytes -- no heap pointers beyond what `Vec` already has above.
+unsafe impl<const N: usize> PGRXSharedMemory for heapless::String<N> {}
+// `IndexSet<T, S, N>` is `IndexMap<T, (), S, N>` underneath, so it inherits exactly
+// `IndexMap`'s inline-storage layout above; only the key bound carries over, since
+// there's no value type to require `Default` for.
+unsafe impl<T: Eq + Hash, S, const N: usize> PGRXSharedMemory for heapless::IndexSet<T, S, N> {}
+// `LinearMap<K, V, N>` stores its N entries in an inline `Vec<(K, V), N>` with linear
+// (not hash-bucketed) lookup -- still just inline storage, no heap pointers.
+unsafe impl<K: Eq, V, const N: usize> PGRXSharedMemory for heapless::LinearMap<K, V, N> {}
+// `BinaryHeap<T, K, N>` is an inline array-backed binary heap; `K` is the zero-sized
+// `Max`/`Min` ordering marker bounded by `heapless::binary_heap::Kind`, not data, so it
+// carries no pointers of its own either.
+unsafe impl<T, K: heapless::binary_heap::Kind, const N: usize> PGRXSharedMemory
+    for heapless::BinaryHeap<T, K, N>
+{
+}
+// `HistoryBuffer<T, N>` is an inline `[MaybeUninit<T>; N]` ring plus a write-cursor
+// `usize` and fill count -- the cursor wrapping around the fixed-size inline buffer on
+// write is itself index arithmetic, not a pointer, so it survives being remapped at a
+// different base address the same as the other containers here.
+unsafe impl<T, const N: usize> PGRXSharedMemory for heapless::HistoryBuffer<T, N> {}